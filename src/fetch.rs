@@ -1,5 +1,12 @@
-use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
 use cmsis_pack::{pack_index::Vidx, utils::FromElem};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
 
 /// Fetches the master VIDX/PIDX file from the ARM server and returns the parsed file.
 pub(crate) fn get_vidx() -> Result<Vidx> {
@@ -15,3 +22,200 @@ pub(crate) fn get_vidx() -> Result<Vidx> {
 
     Ok(vidx)
 }
+
+/// A single pack entry resolved from the master VIDX, ready to be downloaded.
+#[derive(Debug, Clone)]
+pub(crate) struct Pack {
+    pub(crate) vendor: String,
+    pub(crate) name: String,
+    pub(crate) version: String,
+    pub(crate) pack_url: String,
+}
+
+/// Flattens the master VIDX into the individual packs it references.
+pub(crate) fn list_packs() -> Result<Vec<Pack>> {
+    let vidx = get_vidx()?;
+
+    Ok(vidx
+        .pdsc_index
+        .into_iter()
+        .map(|pdsc| Pack {
+            vendor: pdsc.vendor,
+            name: pdsc.name,
+            version: pdsc.version,
+            pack_url: pdsc.url,
+        })
+        .collect())
+}
+
+impl Pack {
+    /// The key a pack is tracked under in the cache and manifest: stable across versions.
+    pub(crate) fn key(&self) -> String {
+        format!("{}.{}", self.vendor, self.name)
+    }
+
+    /// Resolves the fully qualified URL the pack's bytes are downloaded from.
+    pub(crate) fn download_url(&self) -> String {
+        if self.pack_url.starts_with("http") {
+            self.pack_url.clone()
+        } else {
+            format!("https://keilpack.azureedge.net/pack/{}", self.pack_url)
+        }
+    }
+}
+
+/// Path of the fully downloaded `.pack` file for this pack inside the cache directory.
+fn cached_pack_path(cache_dir: &Path, pack: &Pack) -> PathBuf {
+    cache_dir.join(format!("{}-{}.pack", pack.key(), pack.version))
+}
+
+/// Path of the in-progress download for this pack, used to resume interrupted transfers.
+fn partial_pack_path(cache_dir: &Path, pack: &Pack) -> PathBuf {
+    cache_dir.join(format!("{}-{}.pack.part", pack.key(), pack.version))
+}
+
+/// Fetches a pack's bytes, consulting (and populating) a local cache directory so a rerun
+/// doesn't redownload packs it already has.
+pub(crate) fn fetch_pack_cached(
+    cache_dir: &Path,
+    pack: &Pack,
+    progress: &ProgressBar,
+) -> Result<Vec<u8>> {
+    let cached_path = cached_pack_path(cache_dir, pack);
+    if cached_path.exists() {
+        log::debug!("Using cached pack for {} {}.", pack.key(), pack.version);
+        let bytes = fs::read(&cached_path)?;
+        progress.set_length(bytes.len() as u64);
+        progress.set_position(bytes.len() as u64);
+        return Ok(bytes);
+    }
+
+    fs::create_dir_all(cache_dir)?;
+    let partial_path = partial_pack_path(cache_dir, pack);
+    let bytes = download_resumable(pack, &partial_path, progress)?;
+
+    fs::rename(&partial_path, &cached_path)?;
+
+    Ok(bytes)
+}
+
+/// Streams a pack download to `partial_path`, resuming from the file's current length (if
+/// it already exists from an earlier interrupted run) via an HTTP `Range` request.
+fn download_resumable(pack: &Pack, partial_path: &Path, progress: &ProgressBar) -> Result<Vec<u8>> {
+    let url = pack.download_url();
+    let client = reqwest::blocking::Client::new();
+
+    let mut downloaded = if partial_path.exists() {
+        fs::read(partial_path)?
+    } else {
+        Vec::new()
+    };
+
+    let mut response = if downloaded.is_empty() {
+        client.get(&url).send()?
+    } else {
+        log::debug!("Resuming {} from byte {}.", url, downloaded.len());
+        let response = client
+            .get(&url)
+            .header(reqwest::header::RANGE, format!("bytes={}-", downloaded.len()))
+            .send()?;
+
+        if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+            response
+        } else {
+            // The server (or an intermediary) ignored our Range request and is about to send
+            // the full body again; restart from scratch instead of appending that full body
+            // onto the bytes we already have, which would double them up.
+            log::warn!(
+                "Server did not honor the range request for '{}'; restarting download.",
+                url
+            );
+            downloaded.clear();
+            client.get(&url).send()?
+        }
+    };
+
+    if !response.status().is_success() {
+        bail!("Failed to download pack '{}': HTTP {}", url, response.status());
+    }
+
+    progress.set_length(downloaded.len() as u64 + response.content_length().unwrap_or(0));
+    progress.set_position(downloaded.len() as u64);
+
+    // Open once and seek to the resume point instead of rewriting the whole accumulated
+    // buffer to disk on every chunk, which was O(n^2) for multi-hundred-MB packs.
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .open(partial_path)?;
+    file.seek(SeekFrom::Start(downloaded.len() as u64))?;
+    file.set_len(downloaded.len() as u64)?;
+
+    let mut chunk = [0u8; 64 * 1024];
+    loop {
+        let read = response.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+
+        downloaded.extend_from_slice(&chunk[..read]);
+        file.write_all(&chunk[..read])?;
+        progress.inc(read as u64);
+    }
+
+    Ok(downloaded)
+}
+
+/// Tracks the version each pack was last generated from and the family YAML files that
+/// run produced, so incremental mode can skip packs that haven't changed.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub(crate) packs: HashMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ManifestEntry {
+    pub(crate) version: String,
+    pub(crate) generated_families: Vec<String>,
+}
+
+impl Manifest {
+    fn path(cache_dir: &Path) -> PathBuf {
+        cache_dir.join("manifest.yaml")
+    }
+
+    /// Loads the manifest from `cache_dir`, or an empty one if it doesn't exist yet (or is
+    /// corrupt, in which case incremental mode degenerates to "regenerate everything").
+    pub(crate) fn load(cache_dir: &Path) -> Manifest {
+        let path = Self::path(cache_dir);
+        fs::read_to_string(&path)
+            .ok()
+            .and_then(|contents| serde_yaml::from_str(&contents).ok())
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn save(&self, cache_dir: &Path) -> Result<()> {
+        fs::create_dir_all(cache_dir)?;
+        let file = fs::File::create(Self::path(cache_dir))?;
+        serde_yaml::to_writer(file, self)?;
+        Ok(())
+    }
+
+    /// Returns `true` if `pack` was already processed at its current version, meaning
+    /// incremental mode can skip reprocessing it.
+    pub(crate) fn is_unchanged(&self, pack: &Pack) -> bool {
+        self.packs
+            .get(&pack.key())
+            .is_some_and(|entry| entry.version == pack.version)
+    }
+
+    pub(crate) fn record(&mut self, pack: &Pack, generated_families: Vec<String>) {
+        self.packs.insert(
+            pack.key(),
+            ManifestEntry {
+                version: pack.version.clone(),
+                generated_families,
+            },
+        );
+    }
+}
@@ -1,14 +1,63 @@
+use std::collections::HashMap;
 use std::fs::{self};
 use std::io::Read;
+use std::sync::Mutex;
+use std::thread;
 use std::{borrow::Cow, path::Path};
 
 use anyhow::{anyhow, bail, Context, Result};
 use cmsis_pack::pdsc::{Core, Device, Package, Processors};
 use cmsis_pack::utils::FromElem;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use log;
 use probe_rs::config::{Chip, ChipFamily, FlashRegion, MemoryRegion, RamRegion, RawFlashAlgorithm};
+use sha2::{Digest, Sha256};
 
 use crate::fetch::Pack;
+use crate::match_list::MatchList;
+
+/// Maps a flash algorithm's content digest to its canonical name and back, so byte-identical
+/// algorithms collapse onto one name while a name collision with different content gets
+/// disambiguated. Both maps share one `Mutex` so resolving a name is a single atomic step
+/// across worker threads (see `visit_arm_files`).
+#[derive(Debug, Default)]
+pub(crate) struct AlgorithmDigests(Mutex<AlgorithmDigestMaps>);
+
+#[derive(Debug, Default)]
+struct AlgorithmDigestMaps {
+    digest_to_name: HashMap<String, String>,
+    name_to_digest: HashMap<String, String>,
+}
+
+impl AlgorithmDigests {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolves an algorithm's canonical name, suffixing it if it's already claimed by a
+    /// digest other than `digest`.
+    fn canonical_name(&self, digest: &str, proposed_name: &str) -> String {
+        let mut maps = self.0.lock().unwrap();
+
+        if let Some(name) = maps.digest_to_name.get(digest) {
+            return name.clone();
+        }
+
+        let mut candidate = proposed_name.to_string();
+        let mut suffix = 2;
+        while let Some(existing_digest) = maps.name_to_digest.get(&candidate) {
+            if existing_digest == digest {
+                break;
+            }
+            candidate = format!("{}_{}", proposed_name, suffix);
+            suffix += 1;
+        }
+
+        maps.digest_to_name.insert(digest.to_string(), candidate.clone());
+        maps.name_to_digest.insert(candidate.clone(), digest.to_string());
+        candidate
+    }
+}
 
 pub(crate) enum Kind<'a, T>
 where
@@ -22,6 +71,8 @@ pub(crate) fn handle_package<T>(
     pdsc: Package,
     mut kind: Kind<T>,
     families: &mut Vec<ChipFamily>,
+    match_list: &MatchList,
+    algorithm_digests: &AlgorithmDigests,
 ) -> Result<()>
 where
     T: std::io::Seek + std::io::Read,
@@ -31,8 +82,11 @@ where
     devices.sort_by(|a, b| a.0.cmp(&b.0));
 
     for (device_name, device) in devices {
-        // Extract the RAM info from the .pdsc file.
-        let ram = get_ram(&device);
+        // Skip devices the user filtered out before we ever touch their flash algorithms.
+        if !match_list.is_included(&device_name, &device.family) {
+            log::debug!("Skipping '{}', excluded by --include/--exclude.", device_name);
+            continue;
+        }
 
         // Extract the flash algorithm, block & sector size and the erased byte value from the ELF binary.
         let variant_flash_algorithms = device
@@ -66,95 +120,207 @@ where
             )
             .collect::<Vec<_>>();
 
-        // Extract the flash info from the .pdsc file.
-        let mut flash = None;
-        for memory in device.memories.0.values() {
-            if memory.default && memory.access.read && memory.access.execute && !memory.access.write
-            {
-                flash = Some(FlashRegion {
-                    range: memory.start as u32..memory.start as u32 + memory.size as u32,
-                    is_boot_memory: memory.startup,
-                });
-                break;
-            }
-        }
+        let flash_algorithm_digests: Vec<_> = variant_flash_algorithms
+            .iter()
+            .map(algorithm_digest)
+            .collect();
+        let flash_algorithm_names: Vec<_> = variant_flash_algorithms
+            .iter()
+            .zip(&flash_algorithm_digests)
+            .map(|(fa, digest)| algorithm_digests.canonical_name(digest, &fa.name.to_lowercase()))
+            .collect();
 
-        // Get the core type.
-        let core = if let Processors::Symmetric(processor) = &device.processor {
-            match &processor.core {
-                Core::CortexM0 => "M0",
-                Core::CortexM0Plus => "M0",
-                Core::CortexM4 => "M4",
-                Core::CortexM3 => "M3",
-                Core::CortexM33 => "M33",
-                Core::CortexM7 => "M7",
-                c => {
-                    bail!("Core '{:?}' is not yet supported for target generation.", c);
+        // Resolve this device's processor(s): a single entry for symmetric parts, or one
+        // entry per processor for asymmetric (multi-core) parts such as Cortex-M7+M4 combos.
+        let cores = match &device.processor {
+            Processors::Symmetric(processor) => vec![(core_name(&processor.core)?, None)],
+            Processors::Asymmetric(processors) => processors
+                .iter()
+                .map(|processor| Ok((core_name(&processor.core)?, processor.pname.clone())))
+                .collect::<Result<Vec<_>>>()?,
+        };
+        let is_multi_core = cores.len() > 1;
+
+        for (core, pname) in cores {
+            let ram_regions = get_ram_regions(&device, pname.as_deref());
+            let flash_regions = get_flash_regions(&device, pname.as_deref());
+
+            let family_name = if is_multi_core {
+                format!("{}_{}", device.family, core)
+            } else {
+                device.family.clone()
+            };
+
+            // Check if this device family is already known.
+            let mut potential_family = families.iter_mut().find(|family| family.name == family_name);
+
+            let family = if let Some(ref mut family) = potential_family {
+                family
+            } else {
+                families.push(ChipFamily {
+                    name: family_name.into(),
+                    manufacturer: None,
+                    variants: Cow::Owned(Vec::new()),
+                    core: core.into(),
+                    flash_algorithms: Cow::Borrowed(&[]),
+                });
+                // This unwrap is always safe as we insert at least one item previously.
+                families.last_mut().unwrap()
+            };
+
+            for ((fa, digest), canonical_name) in variant_flash_algorithms
+                .iter()
+                .zip(&flash_algorithm_digests)
+                .zip(&flash_algorithm_names)
+            {
+                // Compare by digest, not name: this device's family may already carry the
+                // same algorithm from an earlier pass.
+                let already_present = family
+                    .flash_algorithms
+                    .iter()
+                    .any(|existing| algorithm_digest(existing) == *digest);
+
+                if !already_present {
+                    let mut fa = fa.clone();
+                    fa.name = Cow::Owned(canonical_name.clone());
+                    family.flash_algorithms.to_mut().push(fa);
                 }
             }
-        } else {
-            log::warn!("Asymmetric cores are not supported yet.");
-            ""
-        };
 
-        // Check if this device family is already known.
-        let mut potential_family = families
-            .iter_mut()
-            .find(|family| family.name == device.family);
-
-        let family = if let Some(ref mut family) = potential_family {
-            family
-        } else {
-            families.push(ChipFamily {
-                name: device.family.into(),
-                manufacturer: None,
-                variants: Cow::Owned(Vec::new()),
-                core: core.into(),
-                flash_algorithms: Cow::Borrowed(&[]),
+            let mut memory_map: Vec<MemoryRegion> = Vec::new();
+            memory_map.extend(ram_regions.into_iter().map(MemoryRegion::Ram));
+            memory_map.extend(flash_regions.into_iter().map(MemoryRegion::Flash));
+
+            let variant_name = if is_multi_core {
+                format!("{}_{}", device_name, core)
+            } else {
+                device_name.clone()
+            };
+
+            family.variants.to_mut().push(Chip {
+                name: Cow::Owned(variant_name),
+                part: None,
+                memory_map: Cow::Owned(memory_map),
+                flash_algorithms: Cow::Owned(
+                    flash_algorithm_names
+                        .iter()
+                        .cloned()
+                        .map(Cow::Owned)
+                        .collect(),
+                ),
             });
-            // This unwrap is always safe as we insert at least one item previously.
-            families.last_mut().unwrap()
-        };
+        }
+    }
 
-        let flash_algorithm_names: Vec<_> = variant_flash_algorithms
-            .iter()
-            .map(|fa| fa.name.clone().to_lowercase())
-            .collect();
+    Ok(())
+}
 
-        for fa in variant_flash_algorithms {
-            family.flash_algorithms.to_mut().push(fa);
-        }
+/// Computes the SHA-256 digest of a flash algorithm's instructions and load/PC offsets, used
+/// to recognize byte-identical algorithms regardless of what name their source .FLM carried.
+fn algorithm_digest(fa: &RawFlashAlgorithm) -> String {
+    let mut hasher = Sha256::new();
+
+    for word in &fa.instructions {
+        hasher.update(word.to_le_bytes());
+    }
+    hasher.update(fa.load_address.to_le_bytes());
+    if let Some(pc_init) = fa.pc_init {
+        hasher.update(pc_init.to_le_bytes());
+    }
+    if let Some(pc_uninit) = fa.pc_uninit {
+        hasher.update(pc_uninit.to_le_bytes());
+    }
+    hasher.update(fa.pc_program_page.to_le_bytes());
+    hasher.update(fa.pc_erase_sector.to_le_bytes());
+
+    format!("{:x}", hasher.finalize())
+}
 
-        let mut memory_map: Vec<MemoryRegion> = Vec::new();
-        if let Some(mem) = ram {
-            memory_map.push(MemoryRegion::Ram(mem));
+/// Maps a PDSC `Core` variant to the short core name used in generated target files.
+fn core_name(core: &Core) -> Result<&'static str> {
+    Ok(match core {
+        Core::CortexM0 => "M0",
+        Core::CortexM0Plus => "M0",
+        Core::CortexM3 => "M3",
+        Core::CortexM4 => "M4",
+        Core::CortexM7 => "M7",
+        Core::CortexM23 => "M23",
+        Core::CortexM33 => "M33",
+        Core::CortexM35P => "M35P",
+        Core::CortexM55 => "M55",
+        c => bail!("Core '{:?}' is not yet supported for target generation.", c),
+    })
+}
+
+/// Returns `true` if a memory region with the given `Pname` (if any) is visible to a core
+/// identified by `core_pname`. Regions with no `Pname` are shared by every core.
+fn memory_applies_to_core(memory_pname: Option<&str>, core_pname: Option<&str>) -> bool {
+    match memory_pname {
+        None => true,
+        Some(memory_pname) => Some(memory_pname) == core_pname,
+    }
+}
+
+/// Collects every readable/executable (non-writable) bank visible to `pname` into a
+/// `FlashRegion` each, skipping ranges already covered by one already collected.
+fn get_flash_regions(device: &Device, pname: Option<&str>) -> Vec<FlashRegion> {
+    let mut regions: Vec<FlashRegion> = Vec::new();
+
+    for (name, memory) in sorted_by_default_first(device.memories.0.iter()) {
+        if !memory.access.read || !memory.access.execute || memory.access.write {
+            continue;
+        }
+        if !memory_applies_to_core(memory.pname.as_deref(), pname) {
+            continue;
         }
-        if let Some(mem) = flash {
-            memory_map.push(MemoryRegion::Flash(mem));
+
+        let range = memory.start as u32..memory.start as u32 + memory.size as u32;
+        if regions.iter().any(|region| range_covers(&region.range, &range)) {
+            continue;
         }
 
-        family.variants.to_mut().push(Chip {
-            name: Cow::Owned(device_name),
-            part: None,
-            memory_map: Cow::Owned(memory_map),
-            flash_algorithms: Cow::Owned(
-                flash_algorithm_names.into_iter().map(Cow::Owned).collect(),
-            ),
+        regions.push(FlashRegion {
+            name: name.to_string(),
+            range,
+            is_boot_memory: memory.startup,
         });
     }
 
-    Ok(())
+    regions
+}
+
+/// Orders memories so the `default` region (if any) sorts first, keeping each one's PDSC
+/// name (e.g. `IRAM1`, `CCMRAM`) alongside it.
+fn sorted_by_default_first<'a>(
+    memories: impl Iterator<Item = (&'a String, &'a cmsis_pack::pdsc::Memory)>,
+) -> Vec<(&'a str, &'a cmsis_pack::pdsc::Memory)> {
+    let mut memories: Vec<_> = memories
+        .map(|(name, memory)| (name.as_str(), memory))
+        .collect();
+    memories.sort_by_key(|(_, memory)| !memory.default);
+    memories
+}
+
+/// Returns `true` if `outer` fully contains `inner`, used to drop banks that are just an
+/// alias of an already-collected region.
+fn range_covers(outer: &std::ops::Range<u32>, inner: &std::ops::Range<u32>) -> bool {
+    outer.start <= inner.start && inner.end <= outer.end
 }
 
 // one possible implementation of walking a directory only visiting files
-pub(crate) fn visit_dirs(path: &Path, families: &mut Vec<ChipFamily>) -> Result<()> {
+pub(crate) fn visit_dirs(
+    path: &Path,
+    families: &mut Vec<ChipFamily>,
+    match_list: &MatchList,
+    algorithm_digests: &AlgorithmDigests,
+) -> Result<()> {
     // If we get a dir, look for all .pdsc files.
     for entry in fs::read_dir(path)? {
         let entry = entry?;
         let entry_path = entry.path();
 
         if entry_path.is_dir() {
-            visit_dirs(&entry_path, families)?;
+            visit_dirs(&entry_path, families, match_list, algorithm_digests)?;
         } else if let Some(extension) = entry_path.extension() {
             if extension == "pdsc" {
                 log::info!("Found .pdsc file: {}", path.display());
@@ -163,6 +329,8 @@ pub(crate) fn visit_dirs(path: &Path, families: &mut Vec<ChipFamily>) -> Result<
                     Package::from_path(&entry.path()).map_err(|e| e.compat())?,
                     Kind::Directory(path),
                     families,
+                    match_list,
+                    algorithm_digests,
                 )
                 .context(format!(
                     "Failed to process .pdsc file {}.",
@@ -175,7 +343,12 @@ pub(crate) fn visit_dirs(path: &Path, families: &mut Vec<ChipFamily>) -> Result<
     Ok(())
 }
 
-pub(crate) fn visit_file(path: &Path, families: &mut Vec<ChipFamily>) -> Result<()> {
+pub(crate) fn visit_file(
+    path: &Path,
+    families: &mut Vec<ChipFamily>,
+    match_list: &MatchList,
+    algorithm_digests: &AlgorithmDigests,
+) -> Result<()> {
     log::info!("Trying to open pack file: {}.", path.display());
     // If we get a file, try to unpack it.
     let file = fs::File::open(&path)?;
@@ -199,40 +372,184 @@ pub(crate) fn visit_file(path: &Path, families: &mut Vec<ChipFamily>) -> Result<
 
     drop(pdsc_file);
 
-    handle_package(package, Kind::Archive(&mut archive), families)
+    handle_package(
+        package,
+        Kind::Archive(&mut archive),
+        families,
+        match_list,
+        algorithm_digests,
+    )
 }
 
-pub(crate) fn visit_arm_files(families: &mut Vec<ChipFamily>) -> Result<()> {
+/// Number of packs downloaded and processed concurrently.
+const PACK_WORKER_COUNT: usize = 8;
+
+pub(crate) fn visit_arm_files(
+    families: &mut Vec<ChipFamily>,
+    match_list: &MatchList,
+    algorithm_digests: &AlgorithmDigests,
+    cache_dir: Option<&Path>,
+    incremental: bool,
+) -> Result<()> {
     let packs = crate::fetch::list_packs()?;
 
-    for (i, pack) in packs.iter().enumerate() {
-        log::info!("Working PACK {}/{} ...", i, packs.len());
-        visit_arm_file(families, &pack);
+    // In incremental mode, a pack whose VIDX version matches the one recorded the last time
+    // it was processed is skipped entirely and adds nothing to `families` this run.
+    let manifest = Mutex::new(cache_dir.map(crate::fetch::Manifest::load).unwrap_or_default());
+
+    let multi_progress = MultiProgress::new();
+    let overall_progress = multi_progress.add(ProgressBar::new(packs.len() as u64));
+    overall_progress.set_style(
+        ProgressStyle::default_bar()
+            .template("{prefix:.bold.dim} [{bar:40.cyan/blue}] pack {pos}/{len}")
+            .expect("static progress template is valid"),
+    );
+    overall_progress.set_prefix("Overall");
+
+    // `algorithm_digests` is self-synchronizing and `packs` is read-only, so only the
+    // output families and the manifest need their own locks.
+    let merged_families = Mutex::new(std::mem::take(families));
+    let worker_count = PACK_WORKER_COUNT.min(packs.len()).max(1);
+
+    thread::scope(|scope| {
+        for worker in 0..worker_count {
+            let packs = &packs;
+            let merged_families = &merged_families;
+            let manifest = &manifest;
+            let multi_progress = &multi_progress;
+            let overall_progress = &overall_progress;
+
+            scope.spawn(move || {
+                let pack_progress = multi_progress.add(ProgressBar::new(0));
+                pack_progress.set_style(
+                    ProgressStyle::default_bar()
+                        .template("{prefix} [{bar:40}] {bytes}/{total_bytes}")
+                        .expect("static progress template is valid"),
+                );
+                pack_progress.set_prefix(format!("worker {}", worker));
+
+                for pack in packs.iter().skip(worker).step_by(worker_count) {
+                    if incremental && manifest.lock().unwrap().is_unchanged(pack) {
+                        log::info!("Skipping unchanged pack {} {}.", pack.key(), pack.version);
+                        overall_progress.inc(1);
+                        continue;
+                    }
+
+                    pack_progress.reset();
+                    pack_progress.set_message(pack.name.clone());
+
+                    // Download and parse into a scratch vec so the `merged_families` lock is
+                    // only held for the cheap merge step below.
+                    let mut local_families = Vec::new();
+
+                    visit_arm_file(
+                        &mut local_families,
+                        pack,
+                        match_list,
+                        algorithm_digests,
+                        &pack_progress,
+                        cache_dir,
+                    );
+
+                    let generated_families: Vec<_> = local_families
+                        .iter()
+                        .map(|family| family.name.clone().into_owned())
+                        .collect();
+
+                    let mut merged_families = merged_families.lock().unwrap();
+                    for family in local_families {
+                        merge_family(&mut merged_families, family);
+                    }
+                    drop(merged_families);
+
+                    manifest.lock().unwrap().record(pack, generated_families);
+
+                    overall_progress.inc(1);
+                }
+
+                pack_progress.finish_and_clear();
+            });
+        }
+    });
+
+    overall_progress.finish_with_message("all packs processed");
+
+    if let Some(cache_dir) = cache_dir {
+        manifest.lock().unwrap().save(cache_dir)?;
     }
 
+    *families = merged_families.into_inner().unwrap();
+
     Ok(())
 }
 
-pub(crate) fn visit_arm_file(families: &mut Vec<ChipFamily>, pack: &Pack) {
-    let mut url = pack.PackUrl.clone();
-    if !url.starts_with("http") {
-        url = format!("https://keilpack.azureedge.net/pack/{}", url);
+/// Merges a freshly-generated family into the accumulated result, folding into an existing
+/// entry of the same name rather than emitting a duplicate.
+fn merge_family(families: &mut Vec<ChipFamily>, new_family: ChipFamily) {
+    if let Some(existing) = families.iter_mut().find(|family| family.name == new_family.name) {
+        for fa in new_family.flash_algorithms.into_owned() {
+            // Compare by digest, not name: names are only unique once disambiguated.
+            let digest = algorithm_digest(&fa);
+            let already_present = existing
+                .flash_algorithms
+                .iter()
+                .any(|existing_fa| algorithm_digest(existing_fa) == digest);
+
+            if !already_present {
+                existing.flash_algorithms.to_mut().push(fa);
+            }
+        }
+        for variant in new_family.variants.into_owned() {
+            // A regenerated variant replaces a same-named one, e.g. one preloaded from YAML.
+            let variants = existing.variants.to_mut();
+            if let Some(slot) = variants.iter_mut().find(|v| v.name == variant.name) {
+                *slot = variant;
+            } else {
+                variants.push(variant);
+            }
+        }
+    } else {
+        families.push(new_family);
     }
+}
 
-    log::info!("Downloading {}", url);
-
-    let response = match reqwest::blocking::get(&url) {
-        Ok(response) => response,
-        Err(error) => {
-            log::error!("Failed to download pack '{}': {}", url, error);
-            return;
+pub(crate) fn visit_arm_file(
+    families: &mut Vec<ChipFamily>,
+    pack: &Pack,
+    match_list: &MatchList,
+    algorithm_digests: &AlgorithmDigests,
+    progress: &ProgressBar,
+    cache_dir: Option<&Path>,
+) {
+    let url = pack.download_url();
+
+    let bytes = if let Some(cache_dir) = cache_dir {
+        match crate::fetch::fetch_pack_cached(cache_dir, pack, progress) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                log::error!("Failed to fetch pack '{}': {}", url, error);
+                return;
+            }
         }
-    };
-    let bytes = match response.bytes() {
-        Ok(bytes) => bytes,
-        Err(error) => {
-            log::error!("Failed to get bytes from pack '{}': {}", url, error);
-            return;
+    } else {
+        log::info!("Downloading {}", url);
+
+        let response = match reqwest::blocking::get(&url) {
+            Ok(response) => response,
+            Err(error) => {
+                log::error!("Failed to download pack '{}': {}", url, error);
+                return;
+            }
+        };
+
+        progress.set_length(response.content_length().unwrap_or(0));
+
+        match download_with_progress(response, progress) {
+            Ok(bytes) => bytes,
+            Err(error) => {
+                log::error!("Failed to get bytes from pack '{}': {}", url, error);
+                return;
+            }
         }
     };
 
@@ -279,12 +596,40 @@ pub(crate) fn visit_arm_file(families: &mut Vec<ChipFamily>, pack: &Pack) {
 
     drop(pdsc_file);
 
-    match handle_package(package, Kind::Archive(&mut archive), families) {
+    match handle_package(
+        package,
+        Kind::Archive(&mut archive),
+        families,
+        match_list,
+        algorithm_digests,
+    ) {
         Ok(_) => {}
         Err(err) => log::error!("Something went wrong while handling pack {}: {}", url, err),
     }
 }
 
+/// Streams a pack download into memory in fixed-size chunks, advancing `progress` as bytes
+/// arrive instead of blocking silently until the whole body is buffered.
+fn download_with_progress(
+    mut response: reqwest::blocking::Response,
+    progress: &ProgressBar,
+) -> std::io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 64 * 1024];
+
+    loop {
+        let read = response.read(&mut chunk)?;
+        if read == 0 {
+            break;
+        }
+
+        buffer.extend_from_slice(&chunk[..read]);
+        progress.inc(read as u64);
+    }
+
+    Ok(buffer)
+}
+
 /// Extracts the pdsc out of a ZIP archive.
 pub(crate) fn find_pdsc_in_archive<T>(
     archive: &mut zip::ZipArchive<T>,
@@ -311,15 +656,30 @@ where
     }
 }
 
-pub(crate) fn get_ram(device: &Device) -> Option<RamRegion> {
-    for memory in device.memories.0.values() {
-        if memory.default && memory.access.read && memory.access.write {
-            return Some(RamRegion {
-                range: memory.start as u32..memory.start as u32 + memory.size as u32,
-                is_boot_memory: memory.startup,
-            });
+/// Collects every readable/writable bank visible to `pname` into a `RamRegion` each, skipping
+/// ranges already covered by one already collected.
+pub(crate) fn get_ram_regions(device: &Device, pname: Option<&str>) -> Vec<RamRegion> {
+    let mut regions: Vec<RamRegion> = Vec::new();
+
+    for (name, memory) in sorted_by_default_first(device.memories.0.iter()) {
+        if !memory.access.read || !memory.access.write {
+            continue;
+        }
+        if !memory_applies_to_core(memory.pname.as_deref(), pname) {
+            continue;
+        }
+
+        let range = memory.start as u32..memory.start as u32 + memory.size as u32;
+        if regions.iter().any(|region| range_covers(&region.range, &range)) {
+            continue;
         }
+
+        regions.push(RamRegion {
+            name: name.to_string(),
+            range,
+            is_boot_memory: memory.startup,
+        });
     }
 
-    None
+    regions
 }
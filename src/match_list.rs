@@ -0,0 +1,127 @@
+//! A small include/exclude glob matcher for narrowing which devices get generated,
+//! modeled after the match-list mechanism used by tools like `pxar`.
+
+/// Whether a [`MatchEntry`] includes or excludes the devices it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MatchType {
+    Include,
+    Exclude,
+}
+
+/// A single glob rule, evaluated against both a device's name and its family.
+#[derive(Debug, Clone)]
+pub(crate) struct MatchEntry {
+    pattern: String,
+    ty: MatchType,
+}
+
+impl MatchEntry {
+    pub(crate) fn include(pattern: impl Into<String>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            ty: MatchType::Include,
+        }
+    }
+
+    pub(crate) fn exclude(pattern: impl Into<String>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            ty: MatchType::Exclude,
+        }
+    }
+}
+
+/// An ordered list of include/exclude rules.
+///
+/// Rules are evaluated in order and the *last* rule that matches a device wins.
+/// A device that matches no rule at all defaults to included.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MatchList {
+    entries: Vec<MatchEntry>,
+}
+
+impl MatchList {
+    pub(crate) fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, entry: MatchEntry) {
+        self.entries.push(entry);
+    }
+
+    /// Returns `true` if a device with the given name and family should be kept.
+    pub(crate) fn is_included(&self, device_name: &str, family: &str) -> bool {
+        let mut included = true;
+
+        for entry in &self.entries {
+            if glob_match(&entry.pattern, device_name) || glob_match(&entry.pattern, family) {
+                included = entry.ty == MatchType::Include;
+            }
+        }
+
+        included
+    }
+}
+
+/// Matches `text` against a glob `pattern` supporting `*` (any run of characters),
+/// `?` (any single character) and `[...]` character classes.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    glob_match_inner(&pattern, &text)
+}
+
+fn glob_match_inner(pattern: &[char], text: &[char]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some('*') => {
+            glob_match_inner(&pattern[1..], text)
+                || (!text.is_empty() && glob_match_inner(pattern, &text[1..]))
+        }
+        Some('?') => !text.is_empty() && glob_match_inner(&pattern[1..], &text[1..]),
+        Some('[') => {
+            let close = match pattern.iter().position(|&c| c == ']') {
+                Some(index) if index > 0 => index,
+                _ => return false,
+            };
+
+            if text.is_empty() {
+                return false;
+            }
+
+            let class = &pattern[1..close];
+            let (negate, class) = match class.first() {
+                Some('!') => (true, &class[1..]),
+                _ => (false, class),
+            };
+
+            if class_matches(class, text[0]) != negate {
+                glob_match_inner(&pattern[close + 1..], &text[1..])
+            } else {
+                false
+            }
+        }
+        Some(&c) => !text.is_empty() && text[0] == c && glob_match_inner(&pattern[1..], &text[1..]),
+    }
+}
+
+fn class_matches(class: &[char], c: char) -> bool {
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            if class[i] <= c && c <= class[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if class[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
@@ -0,0 +1,65 @@
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use goblin::elf::Elf;
+use probe_rs::config::RawFlashAlgorithm;
+
+/// The ELF section Keil flash algorithm binaries store their executable code in.
+const CODE_SECTION: &str = "PrgCode";
+
+/// Reads a compiled CMSIS-Pack flash algorithm (a `.FLM`, which is just an ELF binary) and
+/// extracts the instructions and entry points `probe-rs` needs to run it.
+pub(crate) fn extract_flash_algo(
+    mut file: impl Read,
+    path: &Path,
+    default: bool,
+) -> Result<RawFlashAlgorithm> {
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)
+        .with_context(|| format!("Failed to read flash algorithm '{}'.", path.display()))?;
+
+    let elf = Elf::parse(&bytes)
+        .with_context(|| format!("Failed to parse flash algorithm ELF '{}'.", path.display()))?;
+
+    let code_section = elf
+        .section_headers
+        .iter()
+        .find(|section| elf.shdr_strtab.get_at(section.sh_name) == Some(CODE_SECTION))
+        .ok_or_else(|| anyhow!("'{}' has no {} section.", path.display(), CODE_SECTION))?;
+
+    let code = &bytes[code_section.sh_offset as usize
+        ..(code_section.sh_offset + code_section.sh_size) as usize];
+    let instructions = code
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect();
+
+    let symbol_address = |name: &str| -> Option<u32> {
+        elf.syms
+            .iter()
+            .find(|symbol| elf.strtab.get_at(symbol.st_name) == Some(name))
+            .map(|symbol| symbol.st_value as u32)
+    };
+
+    let pc_program_page = symbol_address("ProgramPage")
+        .ok_or_else(|| anyhow!("'{}' has no ProgramPage symbol.", path.display()))?;
+    let pc_erase_sector = symbol_address("EraseSector")
+        .ok_or_else(|| anyhow!("'{}' has no EraseSector symbol.", path.display()))?;
+
+    Ok(RawFlashAlgorithm {
+        name: path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| path.to_string_lossy().into_owned())
+            .into(),
+        default,
+        instructions,
+        load_address: code_section.sh_addr as u32,
+        pc_init: symbol_address("Init"),
+        pc_uninit: symbol_address("UnInit"),
+        pc_program_page,
+        pc_erase_sector,
+        ..Default::default()
+    })
+}